@@ -1,8 +1,23 @@
-use std::borrow::Borrow;
-
 use macroquad::rand::gen_range;
 use macroquad::prelude::*;
 
+// How the simulation treats a domain edge on a given axis. Modes are chosen
+// per-axis so mixed setups (e.g. periodic horizontal, wall-bounded vertical)
+// are possible.
+#[derive(Clone, Copy, PartialEq)]
+enum BoundaryMode {
+    Reflect,
+    Wrap,
+}
+
+// Flip a boundary between the two modes, used by the runtime toggle keys.
+fn toggle_boundary(mode: BoundaryMode) -> BoundaryMode {
+    match mode {
+        BoundaryMode::Reflect => BoundaryMode::Wrap,
+        BoundaryMode::Wrap => BoundaryMode::Reflect,
+    }
+}
+
 #[derive(Clone, Copy)]
 struct Position {
     x: f64,
@@ -17,17 +32,23 @@ struct Velocity {
 
 #[derive(Clone)]
 struct Particle {
+    index: usize,
+    type_index: usize,
     position: Position,
     color: Color,
     velocity: Velocity,
+    radius: f64,
 }
 
 impl Particle {
-    fn new(position: Position, color: Color, velocity: Velocity) -> Particle {
+    fn new(index: usize, type_index: usize, position: Position, color: Color, velocity: Velocity, radius: f64) -> Particle {
         Particle {
+            index,
+            type_index,
             position,
             color,
             velocity,
+            radius,
         }
     }
 }
@@ -38,19 +59,47 @@ struct Rectangle {
     position: Position,
 }
 
-struct QuadTree {
+// Anything stored in the tree exposes its axis-aligned bounding box. Point
+// particles report a box centred on their position; sized entities (walls,
+// agents, obstacles) report their full extent.
+trait Bounded {
+    fn bounds(&self) -> Rectangle;
+}
+
+impl Bounded for Particle {
+    fn bounds(&self) -> Rectangle {
+        return Rectangle {
+            height: 2.0 * self.radius,
+            width: 2.0 * self.radius,
+            position: Position {
+                x: self.position.x - self.radius,
+                y: self.position.y - self.radius,
+            },
+        };
+    }
+}
+
+// Standard AABB overlap test shared by the boundary/range checks.
+fn boxes_overlap(a: &Rectangle, b: &Rectangle) -> bool {
+    return a.position.x <= b.position.x + b.width
+        && a.position.x + a.width >= b.position.x
+        && a.position.y <= b.position.y + b.height
+        && a.position.y + a.height >= b.position.y;
+}
+
+struct QuadTree<T: Bounded + Clone> {
     boundary: Rectangle,
     capacity: u32,
-    points: Vec<Particle>,
+    points: Vec<T>,
     is_divided: bool,
-    top_left: Option<Box<QuadTree>>,
-    top_right: Option<Box<QuadTree>>,
-    bottom_left: Option<Box<QuadTree>>,
-    bottom_right: Option<Box<QuadTree>>,
+    top_left: Option<Box<QuadTree<T>>>,
+    top_right: Option<Box<QuadTree<T>>>,
+    bottom_left: Option<Box<QuadTree<T>>>,
+    bottom_right: Option<Box<QuadTree<T>>>,
 }
 
-impl QuadTree {
-    fn new(boundary: Rectangle, capacity: u32) -> QuadTree {
+impl<T: Bounded + Clone> QuadTree<T> {
+    fn new(boundary: Rectangle, capacity: u32) -> QuadTree<T> {
         QuadTree {
             boundary,
             capacity,
@@ -113,44 +162,53 @@ impl QuadTree {
 
     }
 
-    fn within_boundary(&self, point: &Position) -> bool {
-        let x = point.x;
-        let y = point.y;
+    // An item belongs to this cell only if its whole bounding box fits inside
+    // the cell. Testing the full box (instead of a single point) is what keeps
+    // extended objects straddling a cell edge from being silently dropped.
+    fn within_boundary(&self, b: &Rectangle) -> bool {
         let bx = self.boundary.position.x;
         let by = self.boundary.position.y;
-        let w = self.boundary.width;
-        let h = self.boundary.height;
+        let bw = self.boundary.width;
+        let bh = self.boundary.height;
 
-        return x >= bx && x <= bx + w && y >= by && y <= by + h;
+        return b.position.x >= bx
+            && b.position.x + b.width <= bx + bw
+            && b.position.y >= by
+            && b.position.y + b.height <= by + bh;
     }
 
-    fn insert(&mut self, particle: Option<Particle>) -> Option<Particle> {
+    fn insert(&mut self, item: Option<T>) -> Option<T> {
 
-        if particle.is_none() {
+        if item.is_none() {
             return None;
         }
 
-        if !self.within_boundary(&particle.as_ref().unwrap().position) {
-            return Some(particle.unwrap());
+        let item = item.unwrap();
+
+        if !self.within_boundary(&item.bounds()) {
+            return Some(item);
         }
 
-        if self.points.len() < self.capacity as usize {
-            self.points.push(particle.unwrap());
-            return None;
-        } else {
-            if !self.is_divided {
-                self.subdivide();
+        if !self.is_divided {
+            if self.points.len() < self.capacity as usize {
+                self.points.push(item);
+                return None;
             }
+            self.subdivide();
+        }
 
-            let return_particle = self.top_left.as_mut().unwrap().insert(particle);
-            let return_particle = self.top_right.as_mut().unwrap().insert(return_particle);
-            let return_particle = self.bottom_left.as_mut().unwrap().insert(return_particle);
-            let return_particle = self.bottom_right.as_mut().unwrap().insert(return_particle);
-            
-            return return_particle;
-            
+        // Descend to the deepest child that still fully contains the box. An
+        // item that straddles a child edge is handed back and kept at this level.
+        let item = self.top_left.as_mut().unwrap().insert(Some(item));
+        let item = self.top_right.as_mut().unwrap().insert(item);
+        let item = self.bottom_left.as_mut().unwrap().insert(item);
+        let item = self.bottom_right.as_mut().unwrap().insert(item);
+
+        if let Some(item) = item {
+            self.points.push(item);
         }
 
+        return None;
     }
 
     fn does_range_overlap(&self, range: &Rectangle) -> bool {
@@ -167,14 +225,14 @@ impl QuadTree {
         return x + w >= bx && x <= bx + bw && y + h >= by && y <= by + bh;
     }
 
-    fn query(&self, range: &Rectangle) -> Vec<Particle> {
+    fn query(&self, range: &Rectangle) -> Vec<T> {
         let mut found = Vec::new();
         if !self.does_range_overlap(&range) {
             return found;
         } else {
-            for point in self.points.iter() {
-                if self.within_boundary(point.position.borrow()) {
-                    found.push(point.clone());
+            for item in self.points.iter() {
+                if boxes_overlap(&item.bounds(), range) {
+                    found.push(item.clone());
                 }
             }
 
@@ -184,11 +242,18 @@ impl QuadTree {
                 found.append(&mut self.bottom_left.as_ref().unwrap().query(range));
                 found.append(&mut self.bottom_right.as_ref().unwrap().query(range));
             }
-            
+
         }
         return found;
     }
 
+    // Broadphase: given a moving object's swept AABB, return every stored item
+    // whose box overlaps it. Callers insert all entities (players + particles)
+    // and retrieve collision candidates per object from this one surface.
+    fn broadphase(&self, swept: &Rectangle) -> Vec<T> {
+        return self.query(swept);
+    }
+
     fn clear_quadtree(&mut self) {
         self.points.clear();
         self.is_divided = false;
@@ -199,11 +264,285 @@ impl QuadTree {
     }
 }
 
+// Shared spatial-index surface so the main loop can swap broadphase
+// implementations at runtime and benchmark them against each other. Items are
+// inserted every frame and candidate neighbours retrieved per object.
+trait SpatialIndex {
+    fn insert(&mut self, item: Option<Particle>) -> Option<Particle>;
+    fn query(&self, range: &Rectangle) -> Vec<Particle>;
+    fn clear(&mut self);
+    fn broadphase(&self, swept: &Rectangle) -> Vec<Particle> {
+        return self.query(swept);
+    }
+}
+
+impl SpatialIndex for QuadTree<Particle> {
+    fn insert(&mut self, item: Option<Particle>) -> Option<Particle> {
+        // A box that straddles the domain edge fits no cell; keep it at the root
+        // so it is still found, like the grid keeps any box overlapping the domain.
+        let item = QuadTree::insert(self, item)?;
+        if boxes_overlap(&item.bounds(), &self.boundary) {
+            self.points.push(item);
+            return None;
+        }
+        return Some(item);
+    }
+
+    fn query(&self, range: &Rectangle) -> Vec<Particle> {
+        return QuadTree::query(self, range);
+    }
+
+    fn clear(&mut self) {
+        self.clear_quadtree();
+    }
+}
+
+// A fixed-grid broadphase for near-uniform densities: the domain is partitioned
+// into `cell_w x cell_h` buckets, giving O(1) insertion and O(1) neighbour-cell
+// lookup. For uniform fields this avoids the per-frame cost of rebuilding a
+// recursively subdivided quadtree.
+struct UniformGrid {
+    boundary: Rectangle,
+    cell_w: f64,
+    cell_h: f64,
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<Particle>>,
+}
+
+impl UniformGrid {
+    fn new(boundary: Rectangle, cell_w: f64, cell_h: f64) -> UniformGrid {
+        let cols = (boundary.width / cell_w).ceil().max(1.0) as usize;
+        let rows = (boundary.height / cell_h).ceil().max(1.0) as usize;
+        UniformGrid {
+            boundary,
+            cell_w,
+            cell_h,
+            cols,
+            rows,
+            cells: vec![Vec::new(); cols * rows],
+        }
+    }
+
+    // Clamp a world coordinate to a valid (col, row) cell index.
+    fn cell_index(&self, x: f64, y: f64) -> usize {
+        let mut col = ((x - self.boundary.position.x) / self.cell_w) as isize;
+        let mut row = ((y - self.boundary.position.y) / self.cell_h) as isize;
+        col = col.max(0).min(self.cols as isize - 1);
+        row = row.max(0).min(self.rows as isize - 1);
+        return row as usize * self.cols + col as usize;
+    }
+}
+
+impl SpatialIndex for UniformGrid {
+    fn insert(&mut self, item: Option<Particle>) -> Option<Particle> {
+        if item.is_none() {
+            return None;
+        }
+
+        let item = item.unwrap();
+
+        if !boxes_overlap(&item.bounds(), &self.boundary) {
+            return Some(item);
+        }
+
+        let cell = self.cell_index(item.position.x, item.position.y);
+        self.cells[cell].push(item);
+        return None;
+    }
+
+    fn query(&self, range: &Rectangle) -> Vec<Particle> {
+        let mut found = Vec::new();
+        if !boxes_overlap(range, &self.boundary) {
+            return found;
+        }
+
+        // Walk every cell the range touches — for a one-cell-wide range padded by
+        // the interaction radius this is exactly the home cell plus its 8
+        // neighbours.
+        let min_col = (((range.position.x - self.boundary.position.x) / self.cell_w).floor() as isize).max(0);
+        let max_col = (((range.position.x + range.width - self.boundary.position.x) / self.cell_w).floor() as isize).min(self.cols as isize - 1);
+        let min_row = (((range.position.y - self.boundary.position.y) / self.cell_h).floor() as isize).max(0);
+        let max_row = (((range.position.y + range.height - self.boundary.position.y) / self.cell_h).floor() as isize).min(self.rows as isize - 1);
+
+        let mut row = min_row;
+        while row <= max_row {
+            let mut col = min_col;
+            while col <= max_col {
+                let cell = row as usize * self.cols + col as usize;
+                for item in self.cells[cell].iter() {
+                    if boxes_overlap(&item.bounds(), range) {
+                        found.push(item.clone());
+                    }
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+
+        return found;
+    }
+
+    fn clear(&mut self) {
+        for cell in self.cells.iter_mut() {
+            cell.clear();
+        }
+    }
+}
+
 fn move_particle(particle: &mut Particle, t: f64) {
     particle.position.x = particle.position.x + particle.velocity.x * t;
     particle.position.y = particle.position.y + particle.velocity.y * t;
 }
 
+// Minimum-image displacement on a periodic axis: if the raw separation is more
+// than half the domain the particle is closer across the seam, so fold it. On a
+// non-wrapping axis the raw displacement is returned unchanged.
+fn min_image(mut d: f64, size: f64, wrap: bool) -> f64 {
+    if wrap {
+        if d > size / 2.0 {
+            d -= size;
+        } else if d < -size / 2.0 {
+            d += size;
+        }
+    }
+    return d;
+}
+
+// Gather broadphase candidates for `range`, wrapping the search across any
+// periodic edge the rectangle pokes past. When the rectangle extends beyond an
+// edge on a wrapping axis we also query the complementary rectangle on the
+// opposite side so neighbours across the seam are not missed.
+fn wrapped_query(
+    index: &dyn SpatialIndex,
+    range: &Rectangle,
+    width: f64,
+    height: f64,
+    wrap_x: bool,
+    wrap_y: bool,
+) -> Vec<Particle> {
+    let mut x_offsets = vec![0.0];
+    if wrap_x {
+        if range.position.x < 0.0 {
+            x_offsets.push(width);
+        }
+        if range.position.x + range.width > width {
+            x_offsets.push(-width);
+        }
+    }
+
+    let mut y_offsets = vec![0.0];
+    if wrap_y {
+        if range.position.y < 0.0 {
+            y_offsets.push(height);
+        }
+        if range.position.y + range.height > height {
+            y_offsets.push(-height);
+        }
+    }
+
+    let mut found = Vec::new();
+    for ox in x_offsets.iter() {
+        for oy in y_offsets.iter() {
+            let shifted = Rectangle {
+                height: range.height,
+                width: range.width,
+                position: Position {
+                    x: range.position.x + ox,
+                    y: range.position.y + oy,
+                },
+            };
+            found.append(&mut index.query(&shifted));
+        }
+    }
+
+    return found;
+}
+
+// Continuous (time-of-impact) detection for two finite-sized disks moving at
+// constant velocity over the next `t` units. We solve |dp + dv*s| = r1 + r2 for
+// the earliest root s, i.e. the quadratic a*s^2 + b*s + c = 0 with
+// a = dv.dv, b = 2*dp.dv, c = dp.dp - (r1+r2)^2. A real impact only exists when
+// the pair is approaching (b < 0) and the discriminant is non-negative, and the
+// contact must happen within this frame (0 <= s <= t).
+fn time_of_impact(
+    p1: &Particle,
+    p2: &Particle,
+    t: f64,
+    width: f64,
+    height: f64,
+    wrap_x: bool,
+    wrap_y: bool,
+) -> Option<f64> {
+    let dpx = min_image(p1.position.x - p2.position.x, width, wrap_x);
+    let dpy = min_image(p1.position.y - p2.position.y, height, wrap_y);
+    let dvx = p1.velocity.x - p2.velocity.x;
+    let dvy = p1.velocity.y - p2.velocity.y;
+
+    let sum_radius = p1.radius + p2.radius;
+
+    let a = dvx * dvx + dvy * dvy;
+    let b = 2.0 * (dpx * dvx + dpy * dvy);
+    let c = dpx * dpx + dpy * dpy - sum_radius * sum_radius;
+
+    if a == 0.0 {
+        return None;
+    }
+
+    if b >= 0.0 {
+        return None;
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let s = (-b - discriminant.sqrt()) / (2.0 * a);
+    if s < 0.0 || s > t {
+        return None;
+    }
+
+    return Some(s);
+}
+
+// Resolve an elastic-with-restitution impulse between two equal-mass disks along
+// their contact normal n = (p2 - p1) / |p2 - p1|. With equal masses the exchange
+// is symmetric and rebounds an approaching pair: v1 -= ((1+e)/2)*(dv.n)*n,
+// v2 += ((1+e)/2)*(dv.n)*n.
+fn resolve_collision(
+    particles: &mut [Particle],
+    i: usize,
+    j: usize,
+    restitution: f64,
+    width: f64,
+    height: f64,
+    wrap_x: bool,
+    wrap_y: bool,
+) {
+    let dpx = min_image(particles[j].position.x - particles[i].position.x, width, wrap_x);
+    let dpy = min_image(particles[j].position.y - particles[i].position.y, height, wrap_y);
+    let distance = (dpx * dpx + dpy * dpy).sqrt();
+
+    if distance == 0.0 {
+        return;
+    }
+
+    let nx = dpx / distance;
+    let ny = dpy / distance;
+
+    let dvx = particles[i].velocity.x - particles[j].velocity.x;
+    let dvy = particles[i].velocity.y - particles[j].velocity.y;
+    let dv_dot_n = dvx * nx + dvy * ny;
+
+    let impulse = (1.0 + restitution) / 2.0 * dv_dot_n;
+
+    particles[i].velocity.x -= impulse * nx;
+    particles[i].velocity.y -= impulse * ny;
+    particles[j].velocity.x += impulse * nx;
+    particles[j].velocity.y += impulse * ny;
+}
+
 
 fn draw_rect(rect: &Rectangle) {
     //draw a hollow rectangle
@@ -220,7 +559,7 @@ fn draw_rect(rect: &Rectangle) {
     draw_line(rect.position.x as f32, (rect.position.y + rect.height) as f32, (rect.position.x + rect.width) as f32, (rect.position.y + rect.height) as f32, 1.0, WHITE);
 }
 
-fn draw_quadtree(quadtree: &QuadTree) {
+fn draw_quadtree<T: Bounded + Clone>(quadtree: &QuadTree<T>) {
     draw_rect(&quadtree.boundary);
     if quadtree.is_divided {
         if let Some(top_left) = &quadtree.top_left {
@@ -238,63 +577,73 @@ fn draw_quadtree(quadtree: &QuadTree) {
     }
 }
 
-fn pick_one_color() -> Color {
-    let colors = vec![RED, GREEN, BLUE, YELLOW];
-    let index = gen_range(0, colors.len());
-    return colors[index];
+// Runtime configuration for an N-species particle-life simulation. The
+// attraction `matrix` is square (`num_types` x `num_types`) and asymmetric: how
+// strongly type A is drawn toward type B lives at `matrix[a][b]` and need not
+// equal `matrix[b][a]`.
+struct ParticleLifeConfig {
+    num_types: usize,
+    matrix: Vec<Vec<f64>>,
+    beta: f64,
+    r_max: f64,
+    x_boundary: BoundaryMode,
+    y_boundary: BoundaryMode,
+}
+
+impl ParticleLifeConfig {
+    fn new(num_types: usize) -> ParticleLifeConfig {
+        let mut config = ParticleLifeConfig {
+            num_types,
+            matrix: vec![vec![0.0; num_types]; num_types],
+            beta: 0.3,
+            r_max: 100.0,
+            x_boundary: BoundaryMode::Reflect,
+            y_boundary: BoundaryMode::Reflect,
+        };
+        config.randomize_matrix();
+        return config;
+    }
+
+    // Refill the attraction matrix with fresh random values in [-1, 1]. Because
+    // the matrix is asymmetric each regeneration produces a genuinely different
+    // set of emergent clustering behaviours.
+    fn randomize_matrix(&mut self) {
+        for i in 0..self.num_types {
+            for j in 0..self.num_types {
+                self.matrix[i][j] = gen_range(-1.0, 1.0);
+            }
+        }
+    }
 }
 
-fn colour_attraction_factor_matrix() -> Vec<Vec<f64>> {
-    //red, green, blue, yellow
-    let mut matrix = vec![vec![0.0; 4]; 4];
-    matrix[0][0] = 0.8;
-    matrix[0][1] = -0.8;
-    matrix[0][2] = -0.8;
-    matrix[0][3] = -0.8;
-
-    matrix[1][0] = -0.8;
-    matrix[1][1] = 0.8;
-    matrix[1][2] = -0.8;
-    matrix[1][3] = -0.8;
-
-    matrix[2][0] = -0.8;
-    matrix[2][1] = -0.8;
-    matrix[2][2] = 0.8;
-    matrix[2][3] = -0.8;
-
-    matrix[3][0] = -0.8;
-    matrix[3][1] = -0.8;
-    matrix[3][2] = -0.8;
-    matrix[3][3] = 0.8;
-    
-
-    return matrix;
+// Spread the species evenly around the hue wheel so any `num_types` gets a
+// distinct, saturated colour.
+fn color_for_type(type_index: usize, num_types: usize) -> Color {
+    let hue = type_index as f32 / num_types as f32;
+    return macroquad::color::hsl_to_rgb(hue, 1.0, 0.5);
 }
 
-fn color_to_index(color: Color) -> usize {
-    if color == RED {
-        return 0;
-    } else if color == GREEN {
-        return 1;
-    } else if color == BLUE {
-        return 2;
+fn get_force(r: f64, type_a: usize, type_b: usize, config: &ParticleLifeConfig) -> f64 {
+    let attraction_factor = config.matrix[type_a][type_b];
+    let beta = config.beta;
+    if r < beta {
+        return r / beta - 1.0;
+    } else if beta < r && r < 1.0 {
+        return (1.0 - (2.0 * r - beta).abs() / 1.0 - beta) * attraction_factor;
     } else {
-        return 3;
+        return 0.0;
     }
 }
 
-fn get_force(r: f64, p1_color: Color, p2_color: Color) -> f64 {
-    let color_matrix = colour_attraction_factor_matrix();
-    let c_1_idx = color_to_index(p1_color);
-    let c_2_idx = color_to_index(p2_color);
-    let attraction_factor = color_matrix[c_1_idx][c_2_idx];
-    const BETA : f64 = 0.3;
-    if r < BETA {
-        return r / BETA - 1.0;
-    } else if BETA < r && r < 1.0 {
-        return (1.0 - (2.0 * r - BETA).abs() / 1.0 - BETA) * attraction_factor;
+// Build whichever spatial index is currently selected. Both share the same
+// `SpatialIndex` surface, so the main loop is agnostic to the choice. The grid
+// cell size is taken from the interaction radius so a particle's neighbours
+// always fall in its own cell or the 8 adjacent ones.
+fn build_index(use_grid: bool, boundary: Rectangle, cell_size: f64) -> Box<dyn SpatialIndex> {
+    if use_grid {
+        return Box::new(UniformGrid::new(boundary, cell_size, cell_size));
     } else {
-        return 0.0;
+        return Box::new(QuadTree::<Particle>::new(boundary, 4));
     }
 }
 
@@ -304,92 +653,192 @@ async fn main() {
     let height = macroquad::window::screen_height() as f64;
     let radius = 5.0;
     let speed = 5.0;
-    let num_particles = 1000;
+    let num_particles: usize = 1000;
+    let mut config = ParticleLifeConfig::new(4);
     let mut particles: Vec<Particle> = Vec::new();
 
-    let mut quadtree = QuadTree::new(Rectangle {
-        height: height - 5.0,
-        width: width - 5.0,
+    let mut use_grid = false;
+    let mut index: Box<dyn SpatialIndex> = build_index(use_grid, Rectangle {
+        height,
+        width,
         position: Position {
-            x: 5.0,
-            y: 5.0,
+            x: 0.0,
+            y: 0.0,
         }
-    }, 4);
+    }, config.r_max);
 
-    for _ in 0..num_particles {
+    for index_id in 0..num_particles {
         let start_x = gen_range(100.0, width - 100.0);
         let start_y = gen_range(100.0, height - 100.0);
         let velocity_x = gen_range(-0.0, 0.0);
         let velocity_y = gen_range(-0.0, 0.0);
-        let random_color = pick_one_color();
-        let particle = Particle::new(Position {
+        let type_index = gen_range(0, config.num_types);
+        let random_color = color_for_type(type_index, config.num_types);
+        let particle = Particle::new(index_id, type_index, Position {
             x: start_x as f64,
             y: start_y as f64,
         }, random_color, Velocity {
             x: velocity_x,
             y: velocity_y,
-        });
+        }, radius);
 
         particles.push(particle.clone());
-        quadtree.insert(Some(particle));
+        index.insert(Some(particle));
     }
 
 
-    loop { 
+    let restitution = 0.99;
+
+    loop {
         clear_background(BLACK);
         let t = get_frame_time() as f64 * speed;
-        quadtree.clear_quadtree();
-        for particle in particles.iter_mut() {
+
+        // Regenerate the attraction matrix on demand to explore new behaviours.
+        if is_key_pressed(KeyCode::R) {
+            config.randomize_matrix();
+        }
+
+        // Toggle each axis between reflecting walls and periodic wrap-around so
+        // mixed modes can be exercised at runtime.
+        if is_key_pressed(KeyCode::H) {
+            config.x_boundary = toggle_boundary(config.x_boundary);
+        }
+        if is_key_pressed(KeyCode::V) {
+            config.y_boundary = toggle_boundary(config.y_boundary);
+        }
+
+        // Switch the broadphase between the quadtree and the uniform grid so both
+        // can be benchmarked on the same field.
+        if is_key_pressed(KeyCode::G) {
+            use_grid = !use_grid;
+            index = build_index(use_grid, Rectangle {
+                height,
+                width,
+                position: Position {
+                    x: 0.0,
+                    y: 0.0,
+                }
+            }, config.r_max);
+        }
+
+        // Rebuild the broadphase from the current particle positions so both the
+        // force search and the collision search see every particle this frame.
+        index.clear();
+        for particle in particles.iter() {
+            index.insert(Some(particle.clone()));
+        }
+
+        let wrap_x = config.x_boundary == BoundaryMode::Wrap;
+        let wrap_y = config.y_boundary == BoundaryMode::Wrap;
+
+        // Force pass: accumulate the particle-life attraction/repulsion and fold
+        // it into each velocity. Positions are untouched until the integration
+        // pass so the spatial-index snapshot stays consistent.
+        for i in 0..particles.len() {
+            let particle = particles[i].clone();
             let next_time_position = Position {
                 x: particle.position.x + particle.velocity.x * t,
                 y: particle.position.y + particle.velocity.y * t,
             };
 
-            let mut near_particles = quadtree.query(&Rectangle {
+            let mut near_particles = wrapped_query(index.as_ref(), &Rectangle {
                 height: 1.5 * radius,
                 width: 1.5 * radius,
                 position: Position {
                     x: next_time_position.x - 1.5 * radius,
                     y: next_time_position.y - 1.5 * radius
                 }
-            });
+            }, width, height, wrap_x, wrap_y);
 
             let mut final_force_x = 0.0;
             let mut final_force_y = 0.0;
-            let threshold = 100.0;
+            let threshold = config.r_max;
 
             for near_particle in near_particles.iter_mut() {
                 if near_particle.position.x != particle.position.x && near_particle.position.y != particle.position.y {
-                    let dx = near_particle.position.x - particle.position.x;
-                    let dy = near_particle.position.y - particle.position.y;
+                    let dx = min_image(near_particle.position.x - particle.position.x, width, wrap_x);
+                    let dy = min_image(near_particle.position.y - particle.position.y, height, wrap_y);
                     let distance_squared = dx.powi(2) + dy.powi(2);
                     let distance = distance_squared.sqrt();
                     let direction_x = dx / distance_squared.sqrt();
                     let direction_y = dy / distance_squared.sqrt();
 
                     if distance < threshold {
-                        let force = get_force(distance / threshold, particle.color, near_particle.color);
+                        let force = get_force(distance / threshold, particle.type_index, near_particle.type_index, &config);
                         final_force_x += force * direction_x;
                         final_force_y += force * direction_y;
                     }
                 }
             }
-            
+
             let final_acceleration_x = final_force_x * threshold;
             let final_acceleration_y = final_force_y * threshold;
-          
-            particle.velocity.x = 0.90 * particle.velocity.x + final_acceleration_x * t;
-            particle.velocity.y = 0.90 * particle.velocity.y + final_acceleration_y * t;
 
-            if particle.position.x < radius + 5.0 || particle.position.x > width - radius - 5.0 {
+            particles[i].velocity.x = 0.90 * particle.velocity.x + final_acceleration_x * t;
+            particles[i].velocity.y = 0.90 * particle.velocity.y + final_acceleration_y * t;
+        }
+
+        // Collision pass: gather every candidate pair through the broadphase,
+        // compute a continuous time-of-impact, and resolve the soonest impacts
+        // first so the fastest approaches dominate the frame. The query rectangle
+        // is padded by `max_velocity * t + radius` so fast movers are not missed.
+        let max_velocity = particles.iter().fold(0.0_f64, |acc, p| {
+            let speed = (p.velocity.x * p.velocity.x + p.velocity.y * p.velocity.y).sqrt();
+            return acc.max(speed);
+        });
+        let pad = max_velocity * t + radius;
+
+        let mut collisions: Vec<(f64, usize, usize)> = Vec::new();
+        for i in 0..particles.len() {
+            let particle = particles[i].clone();
+            let candidates = wrapped_query(index.as_ref(), &Rectangle {
+                height: 2.0 * pad,
+                width: 2.0 * pad,
+                position: Position {
+                    x: particle.position.x - pad,
+                    y: particle.position.y - pad,
+                }
+            }, width, height, wrap_x, wrap_y);
+
+            for candidate in candidates.iter() {
+                // Only consider each unordered pair once and never self.
+                if candidate.index <= i {
+                    continue;
+                }
+                // Use the live particle for the candidate's velocity: the index
+                // clone still carries the pre-force snapshot, so mixing it into
+                // `dv` would sample two different instants.
+                if let Some(toi) = time_of_impact(&particle, &particles[candidate.index], t, width, height, wrap_x, wrap_y) {
+                    collisions.push((toi, i, candidate.index));
+                }
+            }
+        }
+
+        collisions.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        for (_, i, j) in collisions.iter() {
+            resolve_collision(&mut particles, *i, *j, restitution, width, height, wrap_x, wrap_y);
+        }
+
+        // Integration pass: handle each boundary by its mode, advance positions
+        // and draw. Reflect axes bounce the velocity at the walls; wrap axes
+        // teleport the particle across the seam to the opposite side.
+        for particle in particles.iter_mut() {
+            if !wrap_x && (particle.position.x < radius + 5.0 || particle.position.x > width - radius - 5.0) {
                 particle.velocity.x = -particle.velocity.x;
             }
-            if particle.position.y < radius + 5.0 || particle.position.y > height - radius - 5.0 {
+            if !wrap_y && (particle.position.y < radius + 5.0 || particle.position.y > height - radius - 5.0) {
                 particle.velocity.y = -particle.velocity.y;
             }
 
             move_particle(particle, t);
-            quadtree.insert(Some(particle.clone()));
+
+            if wrap_x {
+                particle.position.x = particle.position.x.rem_euclid(width);
+            }
+            if wrap_y {
+                particle.position.y = particle.position.y.rem_euclid(height);
+            }
+
             draw_circle(particle.position.x as f32, particle.position.y as f32, radius as f32, particle.color);
         }
         //draw_quadtree(&quadtree);
@@ -404,4 +853,107 @@ fn window_conf() -> Conf {
         window_height: 800,
         ..Default::default()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::prelude::WHITE;
+
+    fn particle_at(index: usize, x: f64, y: f64, vx: f64, vy: f64, radius: f64) -> Particle {
+        return Particle::new(index, 0, Position { x, y }, WHITE, Velocity { x: vx, y: vy }, radius);
+    }
+
+    #[test]
+    fn time_of_impact_detects_approaching_hit() {
+        // A moves right towards a stationary B; boxes of radius 1 touch at s = 8.
+        let a = particle_at(0, 0.0, 0.0, 1.0, 0.0, 1.0);
+        let b = particle_at(1, 10.0, 0.0, 0.0, 0.0, 1.0);
+        let toi = time_of_impact(&a, &b, 100.0, 0.0, 0.0, false, false);
+        assert!(toi.is_some());
+        assert!((toi.unwrap() - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn time_of_impact_ignores_receding_pair() {
+        // B moves away faster than A approaches, so they never meet.
+        let a = particle_at(0, 0.0, 0.0, 1.0, 0.0, 1.0);
+        let b = particle_at(1, 10.0, 0.0, 2.0, 0.0, 1.0);
+        assert!(time_of_impact(&a, &b, 100.0, 0.0, 0.0, false, false).is_none());
+    }
+
+    #[test]
+    fn time_of_impact_ignores_hit_outside_frame() {
+        // The impact is at s = 8 but the frame only lasts 5 units.
+        let a = particle_at(0, 0.0, 0.0, 1.0, 0.0, 1.0);
+        let b = particle_at(1, 10.0, 0.0, 0.0, 0.0, 1.0);
+        assert!(time_of_impact(&a, &b, 5.0, 0.0, 0.0, false, false).is_none());
+    }
+
+    #[test]
+    fn min_image_folds_across_seam() {
+        // Nearer across the wrap than directly.
+        assert!((min_image(9.0, 10.0, true) - (-1.0)).abs() < 1e-9);
+        assert!((min_image(-9.0, 10.0, true) - 1.0).abs() < 1e-9);
+        // Within half the domain, or not wrapping, it is unchanged.
+        assert!((min_image(3.0, 10.0, true) - 3.0).abs() < 1e-9);
+        assert!((min_image(9.0, 10.0, false) - 9.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn boxes_overlap_matches_separation() {
+        let a = Rectangle { height: 2.0, width: 2.0, position: Position { x: 0.0, y: 0.0 } };
+        let overlapping = Rectangle { height: 2.0, width: 2.0, position: Position { x: 1.0, y: 1.0 } };
+        let disjoint = Rectangle { height: 2.0, width: 2.0, position: Position { x: 5.0, y: 5.0 } };
+        assert!(boxes_overlap(&a, &overlapping));
+        assert!(!boxes_overlap(&a, &disjoint));
+    }
+
+    #[test]
+    fn resolve_collision_conserves_momentum_and_energy() {
+        // Head-on, equal masses, perfectly elastic: velocities swap, so both
+        // momentum and kinetic energy are conserved.
+        let mut particles = vec![
+            particle_at(0, 0.0, 0.0, 1.0, 0.0, 1.0),
+            particle_at(1, 10.0, 0.0, -1.0, 0.0, 1.0),
+        ];
+        let momentum_before = particles[0].velocity.x + particles[1].velocity.x;
+        let energy_before = particles[0].velocity.x.powi(2) + particles[1].velocity.x.powi(2);
+
+        resolve_collision(&mut particles, 0, 1, 1.0, 0.0, 0.0, false, false);
+
+        let momentum_after = particles[0].velocity.x + particles[1].velocity.x;
+        let energy_after = particles[0].velocity.x.powi(2) + particles[1].velocity.x.powi(2);
+
+        assert!((momentum_after - momentum_before).abs() < 1e-9);
+        assert!((energy_after - energy_before).abs() < 1e-9);
+        // The pair rebounds rather than accelerating: they now separate.
+        assert!(particles[0].velocity.x < 0.0);
+        assert!(particles[1].velocity.x > 0.0);
+    }
+
+    #[test]
+    fn resolve_collision_dissipates_energy_with_restitution() {
+        let mut particles = vec![
+            particle_at(0, 0.0, 0.0, 1.0, 0.0, 1.0),
+            particle_at(1, 10.0, 0.0, -1.0, 0.0, 1.0),
+        ];
+        let energy_before = particles[0].velocity.x.powi(2) + particles[1].velocity.x.powi(2);
+        resolve_collision(&mut particles, 0, 1, 0.5, 0.0, 0.0, false, false);
+        let energy_after = particles[0].velocity.x.powi(2) + particles[1].velocity.x.powi(2);
+        assert!(energy_after < energy_before);
+    }
+
+    #[test]
+    fn get_force_uses_type_indices() {
+        let mut config = ParticleLifeConfig::new(2);
+        config.matrix = vec![vec![1.0, -1.0], vec![-1.0, 1.0]];
+        // Inside the repulsive core (r < beta) the sign is negative regardless of type.
+        assert!(get_force(0.1, 0, 1, &config) < 0.0);
+        // In the attraction band the matrix entry drives the sign.
+        assert!(get_force(0.35, 0, 0, &config) > 0.0);
+        assert!(get_force(0.35, 0, 1, &config) < 0.0);
+        // Beyond r = 1 there is no force.
+        assert_eq!(get_force(1.5, 0, 0, &config), 0.0);
+    }
 }
\ No newline at end of file